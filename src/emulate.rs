@@ -0,0 +1,359 @@
+//! Userspace emulation of `openat2()`'s path resolution, for kernels that don't have the
+//! syscall (pre-5.6).
+//!
+//! The approach mirrors what the kernel itself does: walk the path one component at a time,
+//! opening each intermediate component with `O_PATH | O_NOFOLLOW` so a concurrent rename can
+//! never substitute a symlink underneath us, and apply the [`ResolveFlags`] semantics at each
+//! step rather than trying to validate the fully-resolved path after the fact.
+
+use std::collections::VecDeque;
+use std::ffi::{CStr, CString, OsString};
+use std::io;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::{Component, Path};
+
+use crate::{OpenHow, ResolveFlags};
+
+/// Matches the kernel's own cap on the number of symlinks followed while resolving one path
+/// (see `MAXSYMLINKS` in the kernel's `namei.c`).
+const MAX_SYMLINKS: u32 = 40;
+
+enum Seg {
+    Name(OsString),
+    Parent,
+}
+
+pub(crate) fn resolve(dirfd: Option<RawFd>, path: &Path, how: &OpenHow) -> io::Result<RawFd> {
+    if path.as_os_str().is_empty() {
+        return Err(io::Error::from_raw_os_error(libc::ENOENT));
+    }
+
+    let is_absolute = path.is_absolute();
+    let want_scope = how.resolve.intersects(ResolveFlags::BENEATH | ResolveFlags::IN_ROOT);
+
+    if is_absolute && how.resolve.contains(ResolveFlags::BENEATH) {
+        // An absolute path can never stay within a scope rooted at `dirfd`.
+        return Err(io::Error::from_raw_os_error(libc::EXDEV));
+    }
+
+    let mut cur = if is_absolute && !want_scope {
+        open_root()?
+    } else {
+        // With `IN_ROOT`, an absolute initial path is resolved relative to the scoped root (i.e.
+        // `dirfd`), same as a relative path, rather than the real filesystem root.
+        start_fd(dirfd)?
+    };
+    let root = if want_scope {
+        Some(dup_cloexec(cur.as_raw_fd())?)
+    } else {
+        None
+    };
+
+    let mut queue = VecDeque::new();
+    append_components(&mut queue, path);
+
+    let mut symlinks_followed = 0u32;
+
+    loop {
+        let seg = match queue.pop_front() {
+            Some(seg) => seg,
+            // The path was ".", "/", or ended in "..": reopen `cur` with the caller's real
+            // flags/mode. As in `try_final()`, `O_NOFOLLOW` is dropped so that it doesn't refuse
+            // the `/proc/self/fd` magic link used for the reopen.
+            None => {
+                let mut how = how.clone();
+                how.flags &= !(libc::O_NOFOLLOW as u64);
+                return reopen_with_flags(&cur, &how);
+            }
+        };
+
+        match seg {
+            Seg::Parent => step_dotdot(&mut cur, &root, how.resolve)?,
+
+            Seg::Name(name) => {
+                let name = CString::new(name.as_bytes())
+                    .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+
+                if queue.is_empty() {
+                    if let Some(fd) =
+                        try_final(&mut cur, &name, how, &root, &mut symlinks_followed, &mut queue)?
+                    {
+                        return Ok(fd);
+                    }
+                    // The final component turned out to be a symlink; its target was pushed
+                    // onto `queue` and `cur` is still the directory containing it.
+                } else {
+                    step_dir(&mut cur, &name, how.resolve, &root, &mut symlinks_followed, &mut queue)?;
+                }
+            }
+        }
+    }
+}
+
+fn effective_no_magiclinks(resolve: ResolveFlags) -> bool {
+    // Per `openat2(2)`, `BENEATH` and `IN_ROOT` currently imply `NO_MAGICLINKS`.
+    resolve.contains(ResolveFlags::NO_MAGICLINKS)
+        || resolve.intersects(ResolveFlags::BENEATH | ResolveFlags::IN_ROOT)
+}
+
+fn append_components(dst: &mut VecDeque<Seg>, path: &Path) {
+    for comp in path.components() {
+        match comp {
+            Component::Normal(s) => dst.push_back(Seg::Name(s.to_os_string())),
+            Component::ParentDir => dst.push_back(Seg::Parent),
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+        }
+    }
+}
+
+fn step_dotdot(cur: &mut OwnedFd, root: &Option<OwnedFd>, resolve: ResolveFlags) -> io::Result<()> {
+    if let Some(root) = root {
+        if same_file(cur.as_raw_fd(), root.as_raw_fd())? {
+            if resolve.contains(ResolveFlags::IN_ROOT) {
+                // Clamp ".." at the scoped root instead of ascending past it.
+                return Ok(());
+            }
+            return Err(io::Error::from_raw_os_error(libc::EXDEV));
+        }
+    }
+
+    let parent = peek_opath(cur.as_raw_fd(), c"..".as_ptr())?;
+    check_xdev(cur.as_raw_fd(), parent.as_raw_fd(), resolve)?;
+    *cur = parent;
+    Ok(())
+}
+
+fn step_dir(
+    cur: &mut OwnedFd,
+    name: &CStr,
+    resolve: ResolveFlags,
+    root: &Option<OwnedFd>,
+    symlinks_followed: &mut u32,
+    queue: &mut VecDeque<Seg>,
+) -> io::Result<()> {
+    let next = peek_opath(cur.as_raw_fd(), name.as_ptr())?;
+    let st = fstat(next.as_raw_fd())?;
+
+    match st.st_mode & libc::S_IFMT {
+        libc::S_IFLNK => {
+            if resolve.contains(ResolveFlags::NO_SYMLINKS) {
+                return Err(io::Error::from_raw_os_error(libc::ELOOP));
+            }
+            follow_symlink(cur, name, &st, resolve, root, symlinks_followed, queue)
+        }
+
+        libc::S_IFDIR => {
+            check_xdev(cur.as_raw_fd(), next.as_raw_fd(), resolve)?;
+            *cur = next;
+            Ok(())
+        }
+
+        // A non-directory, non-symlink component in the middle of the path (e.g. a regular
+        // file) can't be descended into.
+        _ => Err(io::Error::from_raw_os_error(libc::ENOTDIR)),
+    }
+}
+
+/// Returns `Ok(Some(fd))` once the final component has been opened for real, or `Ok(None)` if
+/// the final component was a symlink whose target was pushed onto `queue` for another pass
+/// through the main loop (with `cur` unchanged).
+fn try_final(
+    cur: &mut OwnedFd,
+    name: &CStr,
+    how: &OpenHow,
+    root: &Option<OwnedFd>,
+    symlinks_followed: &mut u32,
+    queue: &mut VecDeque<Seg>,
+) -> io::Result<Option<RawFd>> {
+    match peek_opath(cur.as_raw_fd(), name.as_ptr()) {
+        Ok(peek) => {
+            let st = fstat(peek.as_raw_fd())?;
+
+            if st.st_mode & libc::S_IFMT == libc::S_IFLNK {
+                if how.resolve.contains(ResolveFlags::NO_SYMLINKS) {
+                    return Err(io::Error::from_raw_os_error(libc::ELOOP));
+                }
+                if how.flags & libc::O_NOFOLLOW as u64 != 0 {
+                    // The caller asked not to follow symlinks on the real flags; reopening the
+                    // already-peeked (`O_NOFOLLOW`) fd by its magic `/proc/self/fd` symlink
+                    // produces the same `ELOOP` the kernel would give for a symlink.
+                    return reopen_with_flags(&peek, how).map(Some);
+                }
+                follow_symlink(cur, name, &st, how.resolve, root, symlinks_followed, queue)?;
+                return Ok(None);
+            }
+
+            check_xdev(cur.as_raw_fd(), peek.as_raw_fd(), how.resolve)?;
+            // Reopen the fd we already peeked (via `/proc/self/fd`) instead of re-resolving
+            // `name` a second time, so a concurrent rename can't substitute a symlink for the
+            // final component between the peek and the real open. `peek` isn't a symlink, so the
+            // caller's `O_NOFOLLOW` (if any) is dropped; it would otherwise refuse the
+            // `/proc/self/fd` magic link itself with `ELOOP`.
+            let mut how = how.clone();
+            how.flags &= !(libc::O_NOFOLLOW as u64);
+            reopen_with_flags(&peek, &how).map(Some)
+        }
+
+        Err(e) if e.raw_os_error() != Some(libc::ENOENT) => Err(e),
+        // Doesn't exist yet (e.g. the caller passed `O_CREAT`): fall through to the real
+        // `openat()` and let it create the file. `do_final_open()` adds `O_NOFOLLOW`, so a
+        // symlink created at `name` after the peek fails with `ELOOP` instead of being followed.
+        Err(_) => do_final_open(cur, name, how).map(Some),
+    }
+}
+
+fn follow_symlink(
+    cur: &mut OwnedFd,
+    name: &CStr,
+    st: &libc::stat,
+    resolve: ResolveFlags,
+    root: &Option<OwnedFd>,
+    symlinks_followed: &mut u32,
+    queue: &mut VecDeque<Seg>,
+) -> io::Result<()> {
+    if effective_no_magiclinks(resolve) && st.st_size == 0 {
+        // Magic links (e.g. `/proc/[pid]/fd/*`) report a zero length from `lstat()`, unlike a
+        // normal symlink, which always reports the length of its target text. This can't
+        // distinguish every magic link, but it's the same heuristic used elsewhere to spot them
+        // without parsing `/proc`.
+        return Err(io::Error::from_raw_os_error(libc::ELOOP));
+    }
+
+    *symlinks_followed += 1;
+    if *symlinks_followed > MAX_SYMLINKS {
+        return Err(io::Error::from_raw_os_error(libc::ELOOP));
+    }
+
+    let target = readlinkat(cur.as_raw_fd(), name)?;
+    let target_path = Path::new(&target);
+
+    if target_path.is_absolute() {
+        if resolve.contains(ResolveFlags::BENEATH) {
+            return Err(io::Error::from_raw_os_error(libc::EXDEV));
+        } else if let Some(root) = root {
+            // `IN_ROOT`: an absolute target is resolved relative to the scoped root, not the
+            // real filesystem root.
+            *cur = dup_cloexec(root.as_raw_fd())?;
+        } else {
+            *cur = open_root()?;
+        }
+    }
+
+    let mut expansion = VecDeque::new();
+    append_components(&mut expansion, target_path);
+    expansion.extend(queue.drain(..));
+    *queue = expansion;
+    Ok(())
+}
+
+fn do_final_open(parent: &OwnedFd, name: &CStr, how: &OpenHow) -> io::Result<RawFd> {
+    let fd = unsafe {
+        libc::openat(
+            parent.as_raw_fd(),
+            name.as_ptr(),
+            how.flags as i32 | libc::O_NOFOLLOW,
+            how.mode as u32,
+        )
+    };
+    if fd < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(fd)
+    }
+}
+
+fn reopen_with_flags(cur: &OwnedFd, how: &OpenHow) -> io::Result<RawFd> {
+    // `cur` is a descriptor (usually `O_PATH`) for the fully-resolved path; reopen it with the
+    // caller's real flags via the standard `/proc/self/fd` trick (see the "NOTES" section of
+    // `open(2)`).
+    let proc_path = CString::new(format!("/proc/self/fd/{}", cur.as_raw_fd())).unwrap();
+    let fd = unsafe { libc::open(proc_path.as_ptr(), how.flags as i32, how.mode as u32) };
+    if fd < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(fd)
+    }
+}
+
+fn start_fd(dirfd: Option<RawFd>) -> io::Result<OwnedFd> {
+    match dirfd {
+        Some(fd) => dup_cloexec(fd),
+        None => peek_opath(libc::AT_FDCWD, c".".as_ptr()),
+    }
+}
+
+fn open_root() -> io::Result<OwnedFd> {
+    peek_opath(libc::AT_FDCWD, c"/".as_ptr())
+}
+
+fn peek_opath(dirfd: RawFd, name: *const libc::c_char) -> io::Result<OwnedFd> {
+    let fd = unsafe { libc::openat(dirfd, name, libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC) };
+    if fd < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+}
+
+fn dup_cloexec(fd: RawFd) -> io::Result<OwnedFd> {
+    let dup = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+    if dup < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(unsafe { OwnedFd::from_raw_fd(dup) })
+    }
+}
+
+fn fstat(fd: RawFd) -> io::Result<libc::stat> {
+    let mut st = std::mem::MaybeUninit::<libc::stat>::uninit();
+    let ret = unsafe { libc::fstat(fd, st.as_mut_ptr()) };
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(unsafe { st.assume_init() })
+    }
+}
+
+fn same_file(a: RawFd, b: RawFd) -> io::Result<bool> {
+    let sa = fstat(a)?;
+    let sb = fstat(b)?;
+    Ok(sa.st_dev == sb.st_dev && sa.st_ino == sb.st_ino)
+}
+
+fn check_xdev(parent: RawFd, child: RawFd, resolve: ResolveFlags) -> io::Result<()> {
+    if !resolve.contains(ResolveFlags::NO_XDEV) {
+        return Ok(());
+    }
+    let sp = fstat(parent)?;
+    let sc = fstat(child)?;
+    if sp.st_dev != sc.st_dev {
+        Err(io::Error::from_raw_os_error(libc::EXDEV))
+    } else {
+        Ok(())
+    }
+}
+
+fn readlinkat(dirfd: RawFd, name: &CStr) -> io::Result<OsString> {
+    let mut buf = vec![0u8; 256];
+    loop {
+        let ret = unsafe {
+            libc::readlinkat(
+                dirfd,
+                name.as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_char,
+                buf.len(),
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let ret = ret as usize;
+        if ret < buf.len() {
+            buf.truncate(ret);
+            return Ok(OsString::from_vec(buf));
+        }
+        buf.resize(buf.len() * 2, 0);
+    }
+}
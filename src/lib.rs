@@ -3,9 +3,20 @@ use std::io;
 use std::os::unix::prelude::*;
 use std::path::Path;
 
+mod emulate;
+
 /// Correct on every architecture except alpha (which Rust doesn't support)
 const SYS_OPENAT2: libc::c_long = 437;
 
+/// The size in bytes of the first version of `struct open_how` (`OPEN_HOW_SIZE_VER0` in the
+/// kernel sources), which is also the size of this crate's [`OpenHow`].
+///
+/// This is the `size` every function in this crate passes to the `openat2()` syscall by default.
+/// It's exposed so callers (and [`largest_supported_how_size()`]) can reason about the kernel's
+/// `copy_struct_from_user()`-style extensible-struct handling explicitly instead of hardcoding
+/// `size_of::<OpenHow>()`.
+pub const OPEN_HOW_SIZE_VER0: usize = std::mem::size_of::<OpenHow>();
+
 bitflags::bitflags! {
     /// Flags that modify path resolution.
     #[repr(transparent)]
@@ -130,6 +141,40 @@ impl OpenHow {
             }
         }
     }
+
+    /// Check whether this `OpenHow` depends on behavior that `openat()` and `openat2()` differ
+    /// on.
+    ///
+    /// This returns `true` if any [`ResolveFlags`] are set (`openat()` has no equivalent for path
+    /// resolution restrictions), or if `self.flags`/`self.mode` contain bits that `openat()`
+    /// would silently ignore but `openat2()` rejects with `EINVAL` (see
+    /// [`Self::truncate_flags_mode()`]).
+    ///
+    /// If this returns `false`, an ordinary `openat()` call is semantically equivalent to calling
+    /// [`openat2()`] with this `OpenHow`, so callers can skip the syscall (and the kernel-version
+    /// checks that come with it) entirely. This mirrors the `needs_openat2()` helper from the
+    /// kernel's own `openat2()` selftests.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use openat2::{OpenHow, ResolveFlags};
+    /// let how = OpenHow::new(libc::O_RDONLY, 0);
+    /// assert!(!how.needs_openat2());
+    ///
+    /// let mut how = how;
+    /// how.resolve |= ResolveFlags::NO_SYMLINKS;
+    /// assert!(how.needs_openat2());
+    /// ```
+    pub fn needs_openat2(&self) -> bool {
+        if !self.resolve.is_empty() {
+            return true;
+        }
+
+        let mut truncated = self.clone();
+        truncated.truncate_flags_mode();
+        truncated.flags != self.flags || truncated.mode != self.mode
+    }
 }
 
 /// Call the `openat2()` syscall to open the specified `path`.
@@ -149,19 +194,66 @@ pub fn openat2<P: AsRef<Path>>(dirfd: Option<RawFd>, path: P, how: &OpenHow) ->
     openat2_cstr(dirfd, &path, how)
 }
 
+/// Like [`openat2()`], but returns an [`OwnedFd`] that is closed automatically when dropped,
+/// instead of a bare [`RawFd`] that the caller must remember to close.
+///
+/// Since [`std::fs::File`] implements `From<OwnedFd>`, this can be used directly as
+/// `File::from(openat2_owned(...)?)`.
+#[inline]
+pub fn openat2_owned<P: AsRef<Path>>(
+    dirfd: Option<RawFd>,
+    path: P,
+    how: &OpenHow,
+) -> io::Result<OwnedFd> {
+    openat2(dirfd, path, how).map(|fd| unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
 /// Call the `openat2()` syscall to open the specified `path`.
 ///
 /// This is a lower-level function that is called by [`openat2()`]. See that function's
 /// documentation for more details.
+///
+/// This always passes [`OPEN_HOW_SIZE_VER0`] as the `size` argument; see
+/// [`openat2_cstr_sized()`] if you need control over that.
 #[inline]
 pub fn openat2_cstr(dirfd: Option<RawFd>, path: &CStr, how: &OpenHow) -> io::Result<RawFd> {
+    openat2_cstr_sized(dirfd, path, how, OPEN_HOW_SIZE_VER0)
+}
+
+/// Like [`openat2_cstr()`], but returns an [`OwnedFd`] that is closed automatically when dropped,
+/// instead of a bare [`RawFd`] that the caller must remember to close.
+#[inline]
+pub fn openat2_cstr_owned(dirfd: Option<RawFd>, path: &CStr, how: &OpenHow) -> io::Result<OwnedFd> {
+    openat2_cstr(dirfd, path, how).map(|fd| unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Like [`openat2_cstr()`], but lets the caller specify the `size` argument passed to the
+/// `openat2()` syscall instead of always using [`OPEN_HOW_SIZE_VER0`].
+///
+/// Per the kernel's `copy_struct_from_user()` convention, passing a `size` smaller than
+/// [`OPEN_HOW_SIZE_VER0`] tells the kernel to treat the fields beyond `size` as zero, which can be
+/// useful for emulating how an older (smaller) version of `struct open_how` would behave.
+///
+/// # Panics
+///
+/// Panics if `size` is greater than `size_of::<OpenHow>()`, since this crate has no fields beyond
+/// that to send.
+#[inline]
+pub fn openat2_cstr_sized(
+    dirfd: Option<RawFd>,
+    path: &CStr,
+    how: &OpenHow,
+    size: usize,
+) -> io::Result<RawFd> {
+    assert!(size <= std::mem::size_of::<OpenHow>());
+
     let res = unsafe {
         libc::syscall(
             SYS_OPENAT2,
             dirfd.unwrap_or(libc::AT_FDCWD),
             path.as_ptr(),
             how as *const OpenHow,
-            std::mem::size_of::<OpenHow>(),
+            size,
         )
     };
 
@@ -172,6 +264,79 @@ pub fn openat2_cstr(dirfd: Option<RawFd>, path: &CStr, how: &OpenHow) -> io::Res
     }
 }
 
+/// Open the specified `path` the way [`openat2()`] would, without relying on the syscall being
+/// present.
+///
+/// This resolves `path` one component at a time using `openat()`, applying the semantics of
+/// `how.resolve` (see [`ResolveFlags`]) as it goes rather than validating the result afterwards.
+/// In particular, each intermediate component is opened with `O_PATH | O_NOFOLLOW` before being
+/// inspected, so a concurrent `rename(2)` can't substitute a symlink underneath the resolution
+/// (the same attack the kernel's own selftests exercise against `openat2()`).
+///
+/// This is most useful on kernels older than 5.6, where [`openat2()`] always fails with
+/// `ENOSYS`; see [`openat2_auto()`] for a function that picks whichever is appropriate.
+///
+/// # Notes:
+///
+/// - If `dirfd` is `None`, it will be translated to the current working directory, as with
+///   [`openat2()`].
+/// - The returned file descriptor will NOT have its close-on-exec flag set by default! It is
+///   recommended to include `O_CLOEXEC` in the flags specified using `how` to ensure this is set.
+/// - [`ResolveFlags::NO_XDEV`] is checked by comparing the `st_dev` of each component with its
+///   parent's, so crossing a bind mount of the same filesystem is not detected.
+/// - Magic links are recognized only by the zero size that `lstat()` reports for some of them; a
+///   magic link that reports a nonzero size is treated like an ordinary symlink.
+/// - [`ResolveFlags::CACHED`] is ignored, and `how.flags`/`how.mode` are passed to `open()`
+///   without the `EINVAL` checks that `openat2()` applies to them.
+#[inline]
+pub fn openat2_emulated<P: AsRef<Path>>(
+    dirfd: Option<RawFd>,
+    path: P,
+    how: &OpenHow,
+) -> io::Result<RawFd> {
+    emulate::resolve(dirfd, path.as_ref(), how)
+}
+
+/// Like [`openat2_emulated()`], but returns an [`OwnedFd`] that is closed automatically when
+/// dropped, instead of a bare [`RawFd`] that the caller must remember to close.
+#[inline]
+pub fn openat2_emulated_owned<P: AsRef<Path>>(
+    dirfd: Option<RawFd>,
+    path: P,
+    how: &OpenHow,
+) -> io::Result<OwnedFd> {
+    openat2_emulated(dirfd, path, how).map(|fd| unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Open the specified `path`, using the real [`openat2()`] syscall if it's supported and falling
+/// back on [`openat2_emulated()`] otherwise.
+///
+/// This is the recommended entry point for callers that just want `openat2()`'s semantics
+/// without caring whether the running kernel is new enough to provide the syscall natively.
+#[inline]
+pub fn openat2_auto<P: AsRef<Path>>(
+    dirfd: Option<RawFd>,
+    path: P,
+    how: &OpenHow,
+) -> io::Result<RawFd> {
+    if has_openat2_cached() {
+        openat2(dirfd, path, how)
+    } else {
+        openat2_emulated(dirfd, path, how)
+    }
+}
+
+/// Like [`openat2_auto()`], but returns an [`OwnedFd`] that is closed automatically when dropped,
+/// instead of a bare [`RawFd`] that the caller must remember to close.
+#[inline]
+pub fn openat2_auto_owned<P: AsRef<Path>>(
+    dirfd: Option<RawFd>,
+    path: P,
+    how: &OpenHow,
+) -> io::Result<OwnedFd> {
+    openat2_auto(dirfd, path, how).map(|fd| unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
 /// Probe for the presence of the `openat2()` syscall.
 ///
 /// This checks if [`openat2()`] is supported on the current kernel using the most efficient method
@@ -243,7 +408,7 @@ pub fn supports_open_how(how: &OpenHow) -> bool {
         libc::syscall(
             SYS_OPENAT2,
             libc::AT_FDCWD,
-            b"\0".as_ptr() as *const libc::c_char,
+            c"".as_ptr(),
             how as *const OpenHow,
             std::mem::size_of::<OpenHow>(),
         )
@@ -261,6 +426,150 @@ pub fn supports_open_how(how: &OpenHow) -> bool {
     }
 }
 
+/// All the [`ResolveFlags`] bits this crate knows how to probe individually, for use by
+/// [`supported_resolve_flags()`].
+const ALL_RESOLVE_FLAGS: &[ResolveFlags] = &[
+    ResolveFlags::NO_XDEV,
+    ResolveFlags::NO_MAGICLINKS,
+    ResolveFlags::NO_SYMLINKS,
+    ResolveFlags::BENEATH,
+    ResolveFlags::IN_ROOT,
+    ResolveFlags::CACHED,
+];
+
+/// Probe which individual [`ResolveFlags`] bits the running kernel supports.
+///
+/// `supports_open_how()` only answers yes/no for one fully-built `OpenHow`; this probes each
+/// [`ResolveFlags`] bit on its own (e.g. [`ResolveFlags::CACHED`], added in 5.12, versus the rest,
+/// added in 5.6) against an empty path, using the same `ENOENT`-means-supported/`EINVAL`-means-not
+/// trick as [`supports_open_how()`]. This lets callers write
+/// `if supported_resolve_flags().contains(ResolveFlags::CACHED)` once instead of constructing and
+/// testing probe structs by hand.
+///
+/// Returns [`ResolveFlags::empty()`] if [`openat2()`] isn't supported at all.
+///
+/// The result is cached after the first call, using the same relaxed-atomic approach as
+/// [`has_openat2_cached()`].
+pub fn supported_resolve_flags() -> ResolveFlags {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    // No valid `ResolveFlags` value can ever equal `u64::MAX`, since only a handful of bits are
+    // defined; use it as the "not probed yet" sentinel.
+    static CACHE: AtomicU64 = AtomicU64::new(u64::MAX);
+
+    match CACHE.load(Ordering::Relaxed) {
+        u64::MAX => {
+            let supported = probe_supported_resolve_flags();
+            CACHE.store(supported.bits(), Ordering::Relaxed);
+            supported
+        }
+        bits => ResolveFlags::from_bits_truncate(bits),
+    }
+}
+
+fn probe_supported_resolve_flags() -> ResolveFlags {
+    if !has_openat2_cached() {
+        return ResolveFlags::empty();
+    }
+
+    let mut supported = ResolveFlags::empty();
+
+    for &flag in ALL_RESOLVE_FLAGS {
+        let mut how = OpenHow::new(0, 0);
+        how.resolve = flag;
+
+        if supports_open_how(&how) {
+            supported |= flag;
+        }
+    }
+
+    supported
+}
+
+/// The largest `size` that [`largest_supported_how_size()`] will probe, chosen because the
+/// kernel's `copy_struct_from_user()` refuses any `size` bigger than a page regardless of
+/// content, so there's no point in probing past that.
+const MAX_HOW_PROBE_SIZE: usize = 4096;
+
+/// Probe the running kernel for the largest `open_how` `size` it currently accepts.
+///
+/// `openat2()` uses the same `copy_struct_from_user()` convention as other "extensible struct"
+/// syscalls: the kernel accepts a `size` larger than its own `struct open_how` as long as every
+/// trailing byte is zero, but rejects a larger `size` with nonzero trailing bytes with `E2BIG`.
+/// This function exploits that by poisoning the bytes beyond [`OPEN_HOW_SIZE_VER0`] and
+/// binary-searching for where `E2BIG` starts, which reveals the kernel's actual struct size. That
+/// lets a future version of this crate which adds fields beyond [`OpenHow`]'s current ones check
+/// whether the running kernel will actually see them, instead of guessing from the kernel version.
+///
+/// Returns [`OPEN_HOW_SIZE_VER0`] if [`openat2()`] isn't supported at all.
+///
+/// The result is cached after the first call, using the same relaxed-atomic approach as
+/// [`has_openat2_cached()`].
+pub fn largest_supported_how_size() -> usize {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static CACHE: AtomicUsize = AtomicUsize::new(0);
+
+    match CACHE.load(Ordering::Relaxed) {
+        0 => {
+            let size = probe_largest_how_size();
+            CACHE.store(size, Ordering::Relaxed);
+            size
+        }
+        size => size,
+    }
+}
+
+fn probe_largest_how_size() -> usize {
+    if !has_openat2_cached() {
+        return OPEN_HOW_SIZE_VER0;
+    }
+
+    // `accepted` is always known-good; `rejected` is always known-E2BIG.
+    let mut accepted = OPEN_HOW_SIZE_VER0;
+    let mut rejected = MAX_HOW_PROBE_SIZE + 1;
+
+    while rejected - accepted > 1 {
+        let mid = accepted + (rejected - accepted) / 2;
+        if how_size_is_accepted(mid) {
+            accepted = mid;
+        } else {
+            rejected = mid;
+        }
+    }
+
+    accepted
+}
+
+/// Returns `true` if the kernel doesn't reject (with `E2BIG`) an `open_how` of exactly `size`
+/// bytes whose fields beyond [`OPEN_HOW_SIZE_VER0`] are all nonzero.
+fn how_size_is_accepted(size: usize) -> bool {
+    let mut buf = vec![0u8; size];
+    for byte in &mut buf[OPEN_HOW_SIZE_VER0..] {
+        *byte = 0xff;
+    }
+
+    match unsafe {
+        libc::syscall(
+            SYS_OPENAT2,
+            libc::AT_FDCWD,
+            c"".as_ptr(),
+            buf.as_ptr() as *const OpenHow,
+            size,
+        )
+    } {
+        -1 => unsafe { *libc::__errno_location() != libc::E2BIG },
+
+        fd => {
+            // This shouldn't happen given a poisoned `how`, but clean up just in case.
+            unsafe {
+                libc::close(fd as _);
+            }
+            true
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -306,6 +615,141 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_openat2_owned() {
+        let how = OpenHow::new(libc::O_RDONLY, 0);
+
+        if has_openat2() {
+            // The `OwnedFd` closes itself; there's nothing left to clean up manually.
+            let _file = std::fs::File::from(openat2_owned(None, ".", &how).unwrap());
+        } else {
+            assert_eq!(
+                openat2_owned(None, ".", &how).unwrap_err().raw_os_error(),
+                openat2(None, ".", &how).unwrap_err().raw_os_error(),
+            );
+        }
+    }
+
+    /// A scratch directory (with a fixed layout used by the `openat2_emulated()` tests below)
+    /// that removes itself on drop.
+    struct Fixture(std::path::PathBuf);
+
+    impl Fixture {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("openat2-rs-test-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(dir.join("sub")).unwrap();
+            std::fs::write(dir.join("sub/file.txt"), b"hi").unwrap();
+            std::os::unix::fs::symlink("sub/file.txt", dir.join("link_to_file")).unwrap();
+            std::os::unix::fs::symlink("../../etc/passwd", dir.join("escape_link")).unwrap();
+            Self(dir)
+        }
+
+        fn open_fd(&self) -> RawFd {
+            let path = CString::new(self.0.as_os_str().as_bytes()).unwrap();
+            let fd = unsafe { libc::open(path.as_ptr(), libc::O_RDONLY | libc::O_DIRECTORY) };
+            assert!(fd >= 0);
+            fd
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Opens `path` with the emulated resolution, and, whenever the real syscall is available on
+    /// this kernel, again with [`openat2()`] itself, asserting that both give `expect_err`.
+    fn check_emulated_matches_real(
+        dir_fd: RawFd,
+        path: &str,
+        how: &OpenHow,
+        expect_err: Option<i32>,
+    ) {
+        let emulated = openat2_emulated(Some(dir_fd), path, how).map(|fd| unsafe {
+            libc::close(fd);
+        });
+        assert_eq!(emulated.as_ref().err().and_then(io::Error::raw_os_error), expect_err);
+
+        if has_openat2() {
+            let real = openat2(Some(dir_fd), path, how).map(|fd| unsafe {
+                libc::close(fd);
+            });
+            assert_eq!(real.as_ref().err().and_then(io::Error::raw_os_error), expect_err);
+        }
+    }
+
+    #[test]
+    fn test_openat2_emulated_no_symlinks() {
+        let fixture = Fixture::new("no-symlinks");
+        let dir_fd = fixture.open_fd();
+
+        let mut how = OpenHow::new(libc::O_RDONLY, 0);
+        how.resolve |= ResolveFlags::NO_SYMLINKS;
+        check_emulated_matches_real(dir_fd, "link_to_file", &how, Some(libc::ELOOP));
+
+        // A real (non-symlink) component is unaffected.
+        check_emulated_matches_real(dir_fd, "sub/file.txt", &how, None);
+
+        unsafe {
+            libc::close(dir_fd);
+        }
+    }
+
+    #[test]
+    fn test_openat2_emulated_beneath_blocks_escape() {
+        let fixture = Fixture::new("beneath");
+        let dir_fd = fixture.open_fd();
+
+        let mut how = OpenHow::new(libc::O_RDONLY, 0);
+        how.resolve |= ResolveFlags::BENEATH;
+
+        // `..` that would ascend above `dir_fd`.
+        check_emulated_matches_real(dir_fd, "../escape_link", &how, Some(libc::EXDEV));
+        // A symlink pointing outside the scope.
+        check_emulated_matches_real(dir_fd, "escape_link", &how, Some(libc::EXDEV));
+        // An absolute path can never stay beneath a relative root.
+        check_emulated_matches_real(dir_fd, "/etc/passwd", &how, Some(libc::EXDEV));
+        // Staying inside the scope still works.
+        check_emulated_matches_real(dir_fd, "sub/file.txt", &how, None);
+
+        unsafe {
+            libc::close(dir_fd);
+        }
+    }
+
+    #[test]
+    fn test_openat2_emulated_in_root_clamps_dotdot() {
+        let fixture = Fixture::new("in-root");
+        let dir_fd = fixture.open_fd();
+
+        let mut how = OpenHow::new(libc::O_RDONLY, 0);
+        how.resolve |= ResolveFlags::IN_ROOT;
+
+        // `..` past the scoped root clamps there instead of escaping or erroring.
+        check_emulated_matches_real(dir_fd, "../../sub/file.txt", &how, None);
+        // An absolute path is resolved relative to the scoped root, not the real filesystem root.
+        check_emulated_matches_real(dir_fd, "/sub/file.txt", &how, None);
+
+        unsafe {
+            libc::close(dir_fd);
+        }
+    }
+
+    #[test]
+    fn test_openat2_auto() {
+        let fixture = Fixture::new("auto");
+        let dir_fd = fixture.open_fd();
+
+        let how = OpenHow::new(libc::O_RDONLY, 0);
+        let fd = openat2_auto(Some(dir_fd), "sub/file.txt", &how).unwrap();
+        unsafe {
+            libc::close(fd);
+            libc::close(dir_fd);
+        }
+    }
+
     #[test]
     fn test_openhow_truncate_flags_mode() {
         let mut how = OpenHow::new(0, 0);
@@ -344,4 +788,86 @@ mod tests {
         how.truncate_flags_mode();
         assert_eq!(how.mode, 0o666);
     }
+
+    #[test]
+    fn test_supported_resolve_flags() {
+        let supported = supported_resolve_flags();
+
+        if has_openat2() {
+            // Everything except `CACHED` (added in 5.12) shipped alongside `openat2()` itself in
+            // 5.6, so any kernel with the syscall at all should support them.
+            assert!(supported.contains(
+                ResolveFlags::NO_XDEV
+                    | ResolveFlags::NO_MAGICLINKS
+                    | ResolveFlags::NO_SYMLINKS
+                    | ResolveFlags::BENEATH
+                    | ResolveFlags::IN_ROOT
+            ));
+        } else {
+            assert_eq!(supported, ResolveFlags::empty());
+        }
+        assert!(ResolveFlags::all().contains(supported));
+
+        // Cached calls should agree with the fresh probe.
+        assert_eq!(supported_resolve_flags(), supported);
+    }
+
+    #[test]
+    fn test_largest_supported_how_size() {
+        let size = largest_supported_how_size();
+        assert!(size >= OPEN_HOW_SIZE_VER0);
+
+        // Cached calls should agree with the fresh probe.
+        assert_eq!(largest_supported_how_size(), size);
+
+        if !has_openat2() {
+            assert_eq!(size, OPEN_HOW_SIZE_VER0);
+        }
+    }
+
+    #[test]
+    fn test_openat2_cstr_sized() {
+        let how = OpenHow::new(libc::O_RDONLY, 0);
+        let path = CString::new(".").unwrap();
+
+        if has_openat2() {
+            let fd = openat2_cstr_sized(None, &path, &how, OPEN_HOW_SIZE_VER0).unwrap();
+            unsafe {
+                libc::close(fd);
+            }
+
+            assert_eq!(
+                openat2_cstr_sized(None, &CString::new("./NOEXIST").unwrap(), &how, OPEN_HOW_SIZE_VER0)
+                    .unwrap_err()
+                    .raw_os_error(),
+                Some(libc::ENOENT)
+            );
+        } else {
+            let eno = openat2_cstr_sized(None, &path, &how, OPEN_HOW_SIZE_VER0)
+                .unwrap_err()
+                .raw_os_error()
+                .unwrap();
+            assert!(matches!(eno, libc::ENOSYS | libc::EPERM));
+        }
+    }
+
+    #[test]
+    fn test_openhow_needs_openat2() {
+        let how = OpenHow::new(libc::O_RDONLY, 0);
+        assert!(!how.needs_openat2());
+
+        let how = OpenHow::new(libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC, 0o666);
+        assert!(!how.needs_openat2());
+
+        // openat() silently ignores `mode` without O_CREAT/O_TMPFILE; openat2() would reject it.
+        let mut how = OpenHow::new(libc::O_RDONLY, 0o666);
+        assert!(how.needs_openat2());
+        how.mode = 0;
+        assert!(!how.needs_openat2());
+
+        // Any `resolve` flag means openat() has no equivalent.
+        let mut how = OpenHow::new(libc::O_RDONLY, 0);
+        how.resolve |= ResolveFlags::NO_SYMLINKS;
+        assert!(how.needs_openat2());
+    }
 }